@@ -1,12 +1,58 @@
 use std::{
     collections::HashMap,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    time::Duration,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
 
 use eyre::{bail, ensure, Context, Result};
 use rand::seq::SliceRandom;
-use tokio::{io::AsyncWriteExt, net::TcpListener};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    signal::unix::{signal, SignalKind},
+};
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+
+// Bound on how long a connection may take to drain the quote, so a client that
+// never reads cannot pin a task open forever.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Caps the size of a UDP reply. UDP is spoofable, so an unlimited responder
+// would be a serious DoS amplifier; combined with strict rate limiting this
+// keeps the reply-to-request size ratio small.
+const UDP_MAX_REPLY_BYTES: usize = 512;
+
+const DEFAULT_QUOTES: &[&str] = &[
+    "Quickness is the essence of the war. ~ Sun Tsu",
+    "Pretend inferiority and encourage his arrogance. ~ Sun Tsu",
+    "meow. ~ wffl",
+];
+
+// Reads quotes from the file at `QUOTDD_QUOTES_FILE` (one per line, blank lines
+// ignored), falling back to `DEFAULT_QUOTES` when the variable isn't set.
+fn load_quotes() -> Result<Vec<String>> {
+    let quotes = match std::env::var("QUOTDD_QUOTES_FILE") {
+        Ok(path) => {
+            let contents = std::fs::read_to_string(&path)
+                .wrap_err_with(|| format!("reading quotes file at {path}"))?;
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        }
+        Err(_) => DEFAULT_QUOTES.iter().map(|&s| s.to_owned()).collect(),
+    };
+
+    ensure!(!quotes.is_empty(), "Quotes are empty");
+
+    Ok(quotes)
+}
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
@@ -20,48 +66,235 @@ async fn main() -> Result<()> {
         Err(_) => 17,
     };
 
-    let quotes = [
-        "Quickness is the essence of the war. ~ Sun Tsu",
-        "Pretend inferiority and encourage his arrogance. ~ Sun Tsu",
-        "meow. ~ wffl",
-    ];
+    let quotes = Arc::new(RwLock::new(load_quotes().wrap_err("loading quotes")?));
 
-    ensure!(!quotes.is_empty(), "Quotes are empty");
+    let refill_rate: f64 = match std::env::var("QUOTDD_RATE_LIMIT_REFILL") {
+        Ok(rate) => match rate.parse() {
+            Ok(rate) => rate,
+            Err(err) => {
+                bail!("error: invalid refill rate passed in QUOTDD_RATE_LIMIT_REFILL: {err}");
+            }
+        },
+        Err(_) => 10.0 / 60.0,
+    };
+
+    let burst_capacity: f64 = match std::env::var("QUOTDD_RATE_LIMIT_BURST") {
+        Ok(capacity) => match capacity.parse() {
+            Ok(capacity) => capacity,
+            Err(err) => {
+                bail!("error: invalid burst capacity passed in QUOTDD_RATE_LIMIT_BURST: {err}");
+            }
+        },
+        Err(_) => 10.0,
+    };
+
+    let ipv4_prefix_len: u8 = match std::env::var("QUOTDD_RATE_LIMIT_IPV4_PREFIX") {
+        Ok(prefix) => {
+            match prefix.parse() {
+                Ok(prefix) => prefix,
+                Err(err) => {
+                    bail!("error: invalid prefix length passed in QUOTDD_RATE_LIMIT_IPV4_PREFIX: {err}");
+                }
+            }
+        }
+        Err(_) => 32,
+    };
 
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+    let ipv6_prefix_len: u8 = match std::env::var("QUOTDD_RATE_LIMIT_IPV6_PREFIX") {
+        Ok(prefix) => {
+            match prefix.parse() {
+                Ok(prefix) => prefix,
+                Err(err) => {
+                    bail!("error: invalid prefix length passed in QUOTDD_RATE_LIMIT_IPV6_PREFIX: {err}");
+                }
+            }
+        }
+        Err(_) => 64,
+    };
+
+    let tls_acceptor = load_tls_acceptor().wrap_err("setting up TLS")?;
 
-    eprintln!("info: Listening on socket {}", addr);
+    // Bind the IPv6 unspecified address rather than `0.0.0.0`: on Linux (absent
+    // `IPV6_V6ONLY`) this yields a dual-stack socket that also accepts IPv4
+    // peers as IPv4-mapped IPv6 addresses, so IPv6 clients are actually seen
+    // and prefix-aggregated instead of never reaching the listener.
+    let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
+
+    eprintln!(
+        "info: Listening on socket {} ({})",
+        addr,
+        if tls_acceptor.is_some() {
+            "tls"
+        } else {
+            "plain"
+        }
+    );
 
     let listener = TcpListener::bind(addr)
         .await
         .wrap_err_with(|| format!("binding on port {port}"))?;
 
+    let udp_socket = UdpSocket::bind(addr)
+        .await
+        .wrap_err_with(|| format!("binding UDP on port {port}"))?;
+    let mut udp_buf = [0u8; UDP_MAX_REPLY_BYTES];
+
+    let mut sighup = signal(SignalKind::hangup()).wrap_err("registering SIGHUP handler")?;
+
     let mut reset = tokio::time::interval(Duration::from_secs(60));
 
-    let mut limits = RateLimits::default();
+    let limits = Arc::new(Mutex::new(RateLimits::new(
+        refill_rate,
+        burst_capacity,
+        ipv4_prefix_len,
+        ipv6_prefix_len,
+    )));
 
     loop {
         tokio::select! {
-            result = tcp_loop(&listener, &quotes, &mut limits) => {
-                result?;
+            result = listener.accept() => {
+                let (conn, peer) = result.wrap_err("accepting connection")?;
+                let limits = Arc::clone(&limits);
+                let quotes = Arc::clone(&quotes);
+                let tls_acceptor = tls_acceptor.clone();
+
+                tokio::spawn(async move {
+                    if let Err(err) = handle_connection(conn, peer, &limits, &quotes, tls_acceptor.as_ref()).await {
+                        eprintln!("error: {err:#}");
+                    }
+                });
+            }
+            result = udp_socket.recv_from(&mut udp_buf) => {
+                let (_, peer) = match result {
+                    Ok(received) => received,
+                    Err(err) => {
+                        eprintln!("error: receiving UDP datagram: {err:#}");
+                        continue;
+                    }
+                };
+
+                if limits.lock().unwrap().accept(peer.ip()) {
+                    let quote = pick_quote(&quotes);
+                    let reply = &quote.as_bytes()[..quote.len().min(UDP_MAX_REPLY_BYTES)];
+
+                    if let Err(err) = udp_socket.send_to(reply, peer).await {
+                        eprintln!("error: sending UDP quote: {err:#}");
+                    }
+                }
             }
             _ = reset.tick() => {
-                limits.lower();
+                limits.lock().unwrap().lower();
+            }
+            _ = sighup.recv() => {
+                match load_quotes() {
+                    Ok(new_quotes) => {
+                        *quotes.write().unwrap() = new_quotes;
+                        eprintln!("info: reloaded quotes");
+                    }
+                    Err(err) => eprintln!("error: reloading quotes: {err:#}"),
+                }
             }
         }
     }
 }
 
-async fn tcp_loop(listener: &TcpListener, quotes: &[&str], limits: &mut RateLimits) -> Result<()> {
-    let (mut conn, peer) = listener.accept().await.wrap_err("accepting connection")?;
+// Loads a `TlsAcceptor` from the cert/key pair pointed at by `QUOTDD_TLS_CERT` /
+// `QUOTDD_TLS_KEY`. Both or neither must be set; returns `None` for plaintext operation.
+fn load_tls_acceptor() -> Result<Option<TlsAcceptor>> {
+    let cert_path = std::env::var("QUOTDD_TLS_CERT");
+    let key_path = std::env::var("QUOTDD_TLS_KEY");
+
+    let (cert_path, key_path) = match (cert_path, key_path) {
+        (Ok(cert_path), Ok(key_path)) => (cert_path, key_path),
+        (Err(_), Err(_)) => return Ok(None),
+        _ => bail!("QUOTDD_TLS_CERT and QUOTDD_TLS_KEY must both be set to enable TLS"),
+    };
+
+    let cert_file = std::fs::File::open(&cert_path)
+        .wrap_err_with(|| format!("opening TLS cert at {cert_path}"))?;
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(cert_file))
+        .wrap_err("parsing TLS cert chain")?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    ensure!(!certs.is_empty(), "no certificates found in {cert_path}");
 
-    if !limits.accept(peer.ip()) {
+    let key = load_private_key(&key_path)?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .wrap_err("building TLS server config")?;
+
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+// Reads the first private key found in `path`, accepting PKCS#8
+// (`BEGIN PRIVATE KEY`), PKCS#1 (`BEGIN RSA PRIVATE KEY`) and SEC1
+// (`BEGIN EC PRIVATE KEY`) PEM forms.
+fn load_private_key(path: &str) -> Result<PrivateKey> {
+    let key_file =
+        std::fs::File::open(path).wrap_err_with(|| format!("opening TLS key at {path}"))?;
+    let mut reader = std::io::BufReader::new(key_file);
+
+    loop {
+        match rustls_pemfile::read_one(&mut reader).wrap_err("parsing TLS private key")? {
+            Some(
+                rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => bail!("no private key found in {path}"),
+        }
+    }
+}
+
+async fn handle_connection(
+    conn: TcpStream,
+    peer: SocketAddr,
+    limits: &Mutex<RateLimits>,
+    quotes: &RwLock<Vec<String>>,
+    tls_acceptor: Option<&TlsAcceptor>,
+) -> Result<()> {
+    if !limits.lock().unwrap().accept(peer.ip()) {
+        let mut conn = conn;
         conn.shutdown().await.wrap_err("closing connection")?;
         return Ok(());
     }
 
-    let quote = quotes.choose(&mut rand::thread_rng()).unwrap();
+    let quote = pick_quote(quotes);
+
+    let write = async {
+        match tls_acceptor {
+            Some(acceptor) => {
+                let conn = acceptor.accept(conn).await.wrap_err("TLS handshake")?;
+                write_quote(conn, &quote).await
+            }
+            None => write_quote(conn, &quote).await,
+        }
+    };
+
+    tokio::time::timeout(WRITE_TIMEOUT, write)
+        .await
+        .wrap_err("timed out writing quote")??;
 
+    Ok(())
+}
+
+// Picks a random quote from the current snapshot, cloning it out from under the
+// lock so callers can use it across an `.await` point.
+fn pick_quote(quotes: &RwLock<Vec<String>>) -> String {
+    quotes
+        .read()
+        .unwrap()
+        .choose(&mut rand::thread_rng())
+        .unwrap()
+        .clone()
+}
+
+async fn write_quote(mut conn: impl AsyncWrite + Unpin, quote: &str) -> Result<()> {
     conn.write_all(quote.as_bytes())
         .await
         .wrap_err("writing quote")?;
@@ -71,54 +304,188 @@ async fn tcp_loop(listener: &TcpListener, quotes: &[&str], limits: &mut RateLimi
     Ok(())
 }
 
-// To avoid DoS amplification attacks, we ratelimit our service based on the IP.
+// Masks `addr` down to its network prefix, using `ipv4_prefix_len` or
+// `ipv6_prefix_len` depending on the address family, so that rate limiting
+// aggregates nearby addresses instead of tracking each one individually.
+fn mask_ip(addr: IpAddr, ipv4_prefix_len: u8, ipv6_prefix_len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mut octets = addr.octets();
+            mask_octets(&mut octets, ipv4_prefix_len);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        IpAddr::V6(addr) => {
+            let mut octets = addr.octets();
+            mask_octets(&mut octets, ipv6_prefix_len);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+    }
+}
+
+fn mask_octets<const N: usize>(octets: &mut [u8; N], prefix_len: u8) {
+    let prefix_len = (prefix_len as usize).min(N * 8);
+    for (i, octet) in octets.iter_mut().enumerate() {
+        let bit_offset = i * 8;
+        if bit_offset >= prefix_len {
+            *octet = 0;
+        } else if bit_offset + 8 > prefix_len {
+            *octet &= !0u8 << (bit_offset + 8 - prefix_len);
+        }
+    }
+}
+
+// Entries whose bucket has refilled all the way and has not been touched in this
+// long are dropped on the periodic sweep, so the map doesn't grow without bound
+// under an address-spoofing flood.
+const BUCKET_IDLE_THRESHOLD: Duration = Duration::from_secs(300);
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// To avoid DoS amplification attacks, we ratelimit our service based on the IP,
+// using a token bucket per address: each accept costs one token, tokens refill
+// at `refill_rate` per second up to `burst_capacity`. Addresses are aggregated
+// to a `/ipv4_prefix_len` or `/ipv6_prefix_len` network before bucketing, since
+// per-exact-IP limiting is trivially bypassed by rotating through a /64 (or
+// larger) IPv6 allocation.
 // Sorry, but you're not getting many quotes...
-#[derive(Default)]
 struct RateLimits {
-    ips: HashMap<IpAddr, usize>,
+    ips: HashMap<IpAddr, Bucket>,
+    refill_rate: f64,
+    burst_capacity: f64,
+    ipv4_prefix_len: u8,
+    ipv6_prefix_len: u8,
 }
 
 impl RateLimits {
+    fn new(
+        refill_rate: f64,
+        burst_capacity: f64,
+        ipv4_prefix_len: u8,
+        ipv6_prefix_len: u8,
+    ) -> Self {
+        Self {
+            ips: HashMap::new(),
+            refill_rate,
+            burst_capacity,
+            ipv4_prefix_len,
+            ipv6_prefix_len,
+        }
+    }
+
     fn accept(&mut self, addr: IpAddr) -> bool {
-        let count = self.ips.entry(addr).or_default();
-        let old = *count;
-        *count += 1;
-        old < 10
+        // On a dual-stack socket, IPv4 peers arrive as IPv4-mapped IPv6
+        // addresses (`::ffff:a.b.c.d`); canonicalize back to `IpAddr::V4` first
+        // so they're keyed/masked with `ipv4_prefix_len` instead of every IPv4
+        // host on the internet collapsing onto the single `::` /64 bucket.
+        let addr = addr.to_canonical();
+        let addr = mask_ip(addr, self.ipv4_prefix_len, self.ipv6_prefix_len);
+
+        let now = Instant::now();
+        let burst_capacity = self.burst_capacity;
+        let bucket = self.ips.entry(addr).or_insert_with(|| Bucket {
+            tokens: burst_capacity,
+            last_refill: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * self.refill_rate).min(burst_capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
     }
 
+    // Periodic sweep to bound the size of the map: drop buckets that are both
+    // fully refilled and have been idle for a while.
     fn lower(&mut self) {
-        self.ips
-            .values_mut()
-            .for_each(|v| *v = v.saturating_sub(10));
-        self.ips.retain(|_, v| *v > 0);
+        let now = Instant::now();
+        let burst_capacity = self.burst_capacity;
+        let refill_rate = self.refill_rate;
+
+        self.ips.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.last_refill);
+            let projected =
+                (bucket.tokens + elapsed.as_secs_f64() * refill_rate).min(burst_capacity);
+            !(projected >= burst_capacity && elapsed > BUCKET_IDLE_THRESHOLD)
+        });
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::net::{IpAddr, Ipv4Addr};
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        time::Duration,
+    };
 
-    use crate::RateLimits;
+    use crate::{mask_ip, RateLimits};
 
     #[test]
-    fn ratelimit() {
-        let mut limits = RateLimits::default();
+    fn ratelimit_token_bucket() {
+        let mut limits = RateLimits::new(20.0, 10.0, 32, 64);
         let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
 
         for _ in 0..10 {
             assert!(limits.accept(ip))
         }
 
+        assert!(!limits.accept(ip));
+
+        std::thread::sleep(Duration::from_millis(100));
+
+        assert!(limits.accept(ip));
+    }
+
+    #[test]
+    fn ratelimit_aggregates_by_prefix() {
+        let mut limits = RateLimits::new(0.0, 10.0, 32, 48);
+        let a = "2001:db8:1:1::1".parse().unwrap();
+        let b = "2001:db8:1:2::1".parse().unwrap();
+
         for _ in 0..10 {
-            assert!(!limits.accept(ip))
+            assert!(limits.accept(a))
         }
 
-        limits.lower();
+        // `a` and `b` share a /48, so the bucket is already drained.
+        assert!(!limits.accept(b));
+    }
+
+    #[test]
+    fn ratelimit_canonicalizes_ipv4_mapped_addresses() {
+        // On a dual-stack socket these are how two distinct IPv4 peers show up.
+        let mut limits = RateLimits::new(0.0, 10.0, 32, 64);
+        let a: IpAddr = "::ffff:203.0.113.1".parse().unwrap();
+        let b: IpAddr = "::ffff:203.0.113.2".parse().unwrap();
 
-        assert!(!limits.accept(ip));
+        for _ in 0..10 {
+            assert!(limits.accept(a))
+        }
+
+        // `a` is drained, but `b` is a different IPv4 host and must not share
+        // its bucket just because both are mapped into the same /64.
+        assert!(limits.accept(b));
+    }
 
-        limits.lower();
+    #[test]
+    fn mask_ip_zeroes_bits_below_prefix() {
+        let v4 = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 42));
+        assert_eq!(
+            mask_ip(v4, 24, 64),
+            IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0))
+        );
 
-        assert!(limits.accept(ip));
+        let v6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 3, 4, 5, 6));
+        assert_eq!(
+            mask_ip(v6, 32, 32),
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0))
+        );
     }
 }